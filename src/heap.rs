@@ -1,7 +1,6 @@
 use std::cell::Cell;
 use std::rc::{Rc, Weak};
 
-#[derive(Default)]
 pub struct MutHeap<T>
 where
     T: Ord,
@@ -9,6 +8,17 @@ where
     items: Vec<Wrapper<T>>,
 }
 
+// Written by hand instead of `#[derive(Default)]`: the derive would add a
+// `T: Default` bound even though an empty heap never needs to construct a T.
+impl<T> Default for MutHeap<T>
+where
+    T: Ord,
+{
+    fn default() -> Self {
+        MutHeap { items: Vec::new() }
+    }
+}
+
 impl<T> MutHeap<T>
 where
     T: Ord,
@@ -58,6 +68,32 @@ where
         self.percolate_down(idx);
     }
 
+    /// Removes the element referenced by `handle`, wherever it sits in the
+    /// heap, and restores the heap invariant. The slot it occupied is filled
+    /// by swapping in the last element, which is then percolated up or down
+    /// as needed depending on how it compares to its new neighbors.
+    ///
+    /// Dropping the removed `Wrapper` drops its `Rc<Cell<usize>>`, so any
+    /// other handle still pointing at this element will fail to `upgrade()`
+    /// and hit the existing "handle not present" panic.
+    pub fn remove(&mut self, handle: &Handle) -> Option<T> {
+        println!("removing item @ {:?}", handle);
+        let idx = handle
+            .0
+            .upgrade()
+            .expect("handle not present in heap")
+            .get();
+        let last = self.items.len() - 1;
+        self.items.swap(idx, last);
+        self.items[idx].handle.set(idx);
+        let removed = self.items.pop().unwrap();
+        if idx < self.items.len() {
+            self.percolate_up(idx);
+            self.percolate_down(idx);
+        }
+        Some(removed.item)
+    }
+
     fn percolate_up(&mut self, mut idx: usize) {
         while idx > 0 {
             let mut lowest = idx;
@@ -138,4 +174,31 @@ mod test {
 
         heap.increment(&a, |_| ());
     }
+
+    #[test]
+    fn remove_restores_heap_invariant() {
+        let mut heap = MutHeap::default();
+
+        let a = heap.insert(10);
+        heap.insert(30);
+        let c = heap.insert(20);
+
+        assert_eq!(heap.remove(&a), Some(10));
+        assert_eq!(heap.peek_max(), Some(&30));
+
+        assert_eq!(heap.remove(&c), Some(20));
+        assert_eq!(heap.pop_max(), Some(30));
+        assert_eq!(heap.pop_max(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_on_remove_with_expired_handle() {
+        let mut heap = MutHeap::default();
+
+        let a = heap.insert(10);
+        heap.remove(&a);
+
+        heap.remove(&a);
+    }
 }
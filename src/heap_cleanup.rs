@@ -1,47 +1,80 @@
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::hash::Hash;
 use std::time::{Duration, Instant};
 
-use {Clock, TtlSet};
+use {Clock, TtlMap, TtlSet};
 
-#[derive(Default)]
-pub struct HeapCleanup<C: Clock> {
+pub struct HeapCleanup<K: Hash + Eq + Clone, C: Clock> {
     clock: C,
-    expiration_times: HashMap<u64, Instant>,
-    expiration_index: BinaryHeap<Expiration>,
+    expiration_times: HashMap<K, Instant>,
+    expiration_index: BinaryHeap<Expiration<K>>,
+    capacity: Option<usize>,
+    insertion_order: VecDeque<K>,
 }
 
-struct Expiration {
+// Written by hand instead of `#[derive(Default)]`: the derive adds a bound
+// per struct generic parameter regardless of whether a field actually needs
+// it, and `with_capacity` below needs a `default()` that works without
+// `K: Default`.
+impl<K: Hash + Eq + Clone, C: Clock> Default for HeapCleanup<K, C> {
+    fn default() -> Self {
+        HeapCleanup {
+            clock: C::default(),
+            expiration_times: HashMap::new(),
+            expiration_index: BinaryHeap::new(),
+            capacity: None,
+            insertion_order: VecDeque::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, C: Clock> HeapCleanup<K, C> {
+    /// Bounds the number of live entries to `capacity`. Once the heap holds
+    /// that many entries, an `insert` that doesn't free up room via TTL
+    /// expiration evicts the least-recently-inserted entry, leaving its heap
+    /// slot behind as a ghost for `incremental_clean` to skip over.
+    pub fn with_capacity(capacity: usize) -> HeapCleanup<K, C> {
+        HeapCleanup {
+            capacity: Some(capacity),
+            ..HeapCleanup::default()
+        }
+    }
+}
+
+struct Expiration<K> {
     time: Instant,
-    item: u64,
+    item: K,
 }
-impl Ord for Expiration {
+impl<K> Ord for Expiration<K> {
     // Larger element is the one that expires first, so that a max-heap will pop old elements
     fn cmp(&self, other: &Self) -> Ordering {
         self.time.cmp(&other.time).reverse()
     }
 }
-impl PartialOrd for Expiration {
-    fn partial_cmp(&self, other: &Expiration) -> Option<Ordering> {
+impl<K> PartialOrd for Expiration<K> {
+    fn partial_cmp(&self, other: &Expiration<K>) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
-impl PartialEq for Expiration {
-    fn eq(&self, other: &Expiration) -> bool {
+impl<K> PartialEq for Expiration<K> {
+    fn eq(&self, other: &Expiration<K>) -> bool {
         self.time == other.time
     }
 }
-impl Eq for Expiration {}
+impl<K> Eq for Expiration<K> {}
 
-impl<C: Clock> HeapCleanup<C> {
-    fn incremental_clean(&mut self, threshold: Instant) {
+impl<K: Hash + Eq + Clone, C: Clock> HeapCleanup<K, C> {
+    fn incremental_clean(&mut self, threshold: Instant) -> Vec<K> {
+        let mut expired = Vec::new();
         loop {
             match self.expiration_index.peek() {
                 Some(exp) if exp.time <= threshold => {
                     if let ::std::collections::hash_map::Entry::Occupied(occ) =
-                        self.expiration_times.entry(exp.item)
+                        self.expiration_times.entry(exp.item.clone())
                     {
                         if *occ.get() < threshold {
+                            expired.push(occ.key().clone());
                             occ.remove();
                         }
                     }
@@ -50,23 +83,253 @@ impl<C: Clock> HeapCleanup<C> {
             }
             self.expiration_index.pop();
         }
+        if !expired.is_empty() {
+            self.insertion_order.retain(|k| !expired.contains(k));
+        }
+        expired
+    }
+
+    fn evict_over_capacity(&mut self, now: Instant) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        if self.expiration_times.len() > capacity {
+            self.incremental_clean(now);
+        }
+        while self.expiration_times.len() > capacity {
+            match self.insertion_order.pop_front() {
+                // The stale heap entry left behind is a ghost: incremental_clean
+                // already tolerates entries with no matching expiration_times record.
+                Some(oldest) => self.expiration_times.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    /// The expiration at the top of the heap. Overwritten and evicted entries
+    /// leave ghost slots behind, so this can be earlier than any live entry's
+    /// expiration, or `Some` when no entry is live.
+    pub fn next_expiration(&self) -> Option<Instant> {
+        self.expiration_index.peek().map(|exp| exp.time)
+    }
+
+    /// How long until the soonest expiration, so a caller can arm a single
+    /// wakeup timer instead of polling `contains`.
+    pub fn time_until_next(&mut self) -> Option<Duration> {
+        let next = self.next_expiration()?;
+        let now = self.clock.now();
+        Some(next.saturating_duration_since(now))
+    }
+
+    /// Pops every entry due by `now` off the heap and yields its key, instead
+    /// of discarding them silently.
+    pub fn drain_expired(&mut self) -> impl Iterator<Item = K> {
+        let now = self.clock.now();
+        self.incremental_clean(now).into_iter()
+    }
+
+    /// Expires due entries, then drops any surviving key for which `f`
+    /// returns `false`.
+    pub fn retain<F: FnMut(&K) -> bool>(&mut self, mut f: F) {
+        let now = self.clock.now();
+        self.incremental_clean(now);
+        let insertion_order = &mut self.insertion_order;
+        self.expiration_times.retain(|key, _| {
+            let keep = f(key);
+            if !keep {
+                insertion_order.retain(|k| k != key);
+            }
+            keep
+        });
     }
 }
 
-impl<C: Clock> TtlSet for HeapCleanup<C> {
-    fn insert(&mut self, item: u64, duration: Duration) {
-        let time = self.clock.now() + duration;
-        self.expiration_times.insert(item, time);
-        self.expiration_index.push(Expiration { item, time });
+impl<K: Hash + Eq + Clone, C: Clock> TtlSet<K> for HeapCleanup<K, C> {
+    fn insert(&mut self, item: K, duration: Duration) {
+        let now = self.clock.now();
+        let time = now + duration;
+        self.expiration_times.insert(item.clone(), time);
+        self.expiration_index.push(Expiration {
+            item: item.clone(),
+            time,
+        });
+        self.insertion_order.retain(|k| k != &item);
+        self.insertion_order.push_back(item);
+        self.evict_over_capacity(now);
     }
 
-    fn contains(&mut self, item: u64) -> bool {
+    fn contains(&mut self, item: K) -> bool {
         let now = self.clock.now();
         self.incremental_clean(now);
         self.expiration_times.contains_key(&item)
     }
 }
 
+/// Like `HeapCleanup`, but stores a value alongside each key instead of just
+/// tracking membership.
+pub struct HeapCleanupMap<K: Hash + Eq + Clone, V, C: Clock> {
+    clock: C,
+    expiration_times: HashMap<K, Instant>,
+    expiration_index: BinaryHeap<Expiration<K>>,
+    values: HashMap<K, V>,
+    capacity: Option<usize>,
+    insertion_order: VecDeque<K>,
+}
+
+// Written by hand instead of `#[derive(Default)]`, for the same reason as
+// `HeapCleanup` above: the derive would tie this impl to `K: Default` and
+// `V: Default` bounds that `with_capacity` below has no way to satisfy.
+impl<K: Hash + Eq + Clone, V, C: Clock> Default for HeapCleanupMap<K, V, C> {
+    fn default() -> Self {
+        HeapCleanupMap {
+            clock: C::default(),
+            expiration_times: HashMap::new(),
+            expiration_index: BinaryHeap::new(),
+            values: HashMap::new(),
+            capacity: None,
+            insertion_order: VecDeque::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, C: Clock> HeapCleanupMap<K, V, C> {
+    /// Bounds the number of entries in the map to `capacity`, the same way
+    /// `HeapCleanup::with_capacity` bounds the set: once the heap holds that
+    /// many keys, an `insert` that doesn't free up room via TTL expiration
+    /// evicts the least-recently-inserted key and its value, again leaving a
+    /// ghost heap slot behind rather than removing it outright.
+    pub fn with_capacity(capacity: usize) -> HeapCleanupMap<K, V, C> {
+        HeapCleanupMap {
+            capacity: Some(capacity),
+            ..HeapCleanupMap::default()
+        }
+    }
+
+    fn incremental_clean(&mut self, threshold: Instant) -> Vec<(K, V)> {
+        let mut expired = Vec::new();
+        loop {
+            match self.expiration_index.peek() {
+                Some(exp) if exp.time <= threshold => {
+                    if let ::std::collections::hash_map::Entry::Occupied(occ) =
+                        self.expiration_times.entry(exp.item.clone())
+                    {
+                        if *occ.get() < threshold {
+                            let key = occ.key().clone();
+                            occ.remove();
+                            if let Some(value) = self.values.remove(&key) {
+                                expired.push((key, value));
+                            }
+                        }
+                    }
+                }
+                _ => break,
+            }
+            self.expiration_index.pop();
+        }
+        if !expired.is_empty() {
+            self.insertion_order
+                .retain(|k| !expired.iter().any(|(id, _)| id == k));
+        }
+        expired
+    }
+
+    fn evict_over_capacity(&mut self, now: Instant) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        if self.expiration_times.len() > capacity {
+            self.incremental_clean(now);
+        }
+        while self.expiration_times.len() > capacity {
+            match self.insertion_order.pop_front() {
+                Some(oldest) => {
+                    self.remove_entry(&oldest);
+                }
+                None => break,
+            };
+        }
+    }
+
+    /// The expiration at the top of the heap. Overwritten, evicted and removed
+    /// entries leave ghost slots behind, so this can be earlier than any live
+    /// entry's expiration, or `Some` when the map is empty.
+    pub fn next_expiration(&self) -> Option<Instant> {
+        self.expiration_index.peek().map(|exp| exp.time)
+    }
+
+    /// How long until the soonest expiration, so a caller can arm a single
+    /// wakeup timer instead of polling `get`.
+    pub fn time_until_next(&mut self) -> Option<Duration> {
+        let next = self.next_expiration()?;
+        let now = self.clock.now();
+        Some(next.saturating_duration_since(now))
+    }
+
+    /// Pops every entry due by `now` off the heap and yields its
+    /// `(key, value)`, instead of discarding them silently.
+    pub fn drain_expired(&mut self) -> impl Iterator<Item = (K, V)> {
+        let now = self.clock.now();
+        self.incremental_clean(now).into_iter()
+    }
+
+    /// Expires due entries, then drops any surviving `(key, value)` pair
+    /// whose key fails `f`.
+    pub fn retain<F: FnMut(&K) -> bool>(&mut self, mut f: F) {
+        let now = self.clock.now();
+        self.incremental_clean(now);
+        let values = &mut self.values;
+        self.expiration_times.retain(|key, _| {
+            let keep = f(key);
+            if !keep {
+                values.remove(key);
+            }
+            keep
+        });
+    }
+
+    fn remove_entry(&mut self, key: &K) -> Option<V> {
+        // The stale heap entry left behind is a ghost: incremental_clean
+        // already tolerates removing a key that's no longer registered.
+        self.expiration_times.remove(key);
+        self.insertion_order.retain(|k| k != key);
+        self.values.remove(key)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, C: Clock> TtlMap<K, V> for HeapCleanupMap<K, V, C> {
+    fn insert(&mut self, key: K, value: V, duration: Duration) {
+        let now = self.clock.now();
+        let time = now + duration;
+        self.expiration_times.insert(key.clone(), time);
+        self.values.insert(key.clone(), value);
+        self.expiration_index.push(Expiration {
+            item: key.clone(),
+            time,
+        });
+        self.insertion_order.retain(|k| k != &key);
+        self.insertion_order.push_back(key);
+        self.evict_over_capacity(now);
+    }
+
+    fn get(&mut self, key: K) -> Option<&V> {
+        let now = self.clock.now();
+        self.incremental_clean(now);
+        self.values.get(&key)
+    }
+
+    fn get_mut(&mut self, key: K) -> Option<&mut V> {
+        let now = self.clock.now();
+        self.incremental_clean(now);
+        self.values.get_mut(&key)
+    }
+
+    fn remove(&mut self, key: K) -> Option<V> {
+        self.remove_entry(&key)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -74,7 +337,7 @@ mod test {
 
     #[test]
     fn tree_cleanup_smoke_test() {
-        let mut m = HeapCleanup::<FakeClock>::default();
+        let mut m = HeapCleanup::<u64, FakeClock>::default();
 
         assert!(!m.contains(0));
 
@@ -90,7 +353,7 @@ mod test {
 
     #[test]
     fn overwriting_entries_wiped_old_expirations() {
-        let mut m = HeapCleanup::<FakeClock>::default();
+        let mut m = HeapCleanup::<u64, FakeClock>::default();
 
         assert!(!m.contains(0));
 
@@ -103,4 +366,159 @@ mod test {
         m.clock.advance(Duration::from_secs(100));
         assert!(m.contains(0));
     }
+
+    #[test]
+    fn heap_cleanup_capacity_evicts_least_recently_inserted() {
+        let mut m = HeapCleanup::<u64, FakeClock>::with_capacity(2);
+
+        m.insert(0, Duration::from_secs(100));
+        m.insert(1, Duration::from_secs(100));
+        assert!(m.contains(0));
+        assert!(m.contains(1));
+
+        m.insert(2, Duration::from_secs(100));
+        assert!(!m.contains(0));
+        assert!(m.contains(1));
+        assert!(m.contains(2));
+    }
+
+    #[test]
+    fn heap_cleanup_capacity_refresh_moves_key_to_back_of_queue() {
+        let mut m = HeapCleanup::<u64, FakeClock>::with_capacity(2);
+
+        m.insert(0, Duration::from_secs(100));
+        m.insert(1, Duration::from_secs(100));
+        m.insert(0, Duration::from_secs(100));
+
+        m.insert(2, Duration::from_secs(100));
+        assert!(m.contains(0));
+        assert!(!m.contains(1));
+        assert!(m.contains(2));
+    }
+
+    #[test]
+    fn heap_cleanup_next_expiration() {
+        let mut m = HeapCleanup::<u64, FakeClock>::default();
+
+        assert_eq!(m.next_expiration(), None);
+
+        m.insert(0, Duration::from_secs(15));
+        m.insert(1, Duration::from_secs(5));
+        assert_eq!(m.next_expiration(), Some(m.expiration_times[&1]));
+
+        assert!(m.time_until_next().unwrap() <= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn heap_cleanup_drain_expired() {
+        let mut m = HeapCleanup::<u64, FakeClock>::default();
+
+        m.insert(0, Duration::from_secs(5));
+        m.insert(1, Duration::from_secs(100));
+
+        m.clock.advance(Duration::from_secs(10));
+        let drained: Vec<u64> = m.drain_expired().collect();
+        assert_eq!(drained, vec![0]);
+        assert!(!m.contains(0));
+        assert!(m.contains(1));
+    }
+
+    #[test]
+    fn heap_cleanup_retain() {
+        let mut m = HeapCleanup::<u64, FakeClock>::default();
+
+        m.insert(0, Duration::from_secs(100));
+        m.insert(1, Duration::from_secs(100));
+        m.insert(2, Duration::from_secs(100));
+
+        m.retain(|&key| key != 1);
+
+        assert!(m.contains(0));
+        assert!(!m.contains(1));
+        assert!(m.contains(2));
+    }
+
+    #[test]
+    fn heap_cleanup_map_smoke_test() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::default();
+
+        assert_eq!(m.get(0), None);
+
+        m.insert(0, "hello", Duration::from_secs(15));
+        assert_eq!(m.get(0), Some(&"hello"));
+
+        m.clock.advance(Duration::from_secs(10));
+        assert_eq!(m.get(0), Some(&"hello"));
+
+        m.clock.advance(Duration::from_secs(10));
+        assert_eq!(m.get(0), None);
+    }
+
+    #[test]
+    fn heap_cleanup_map_drain_expired() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::default();
+
+        m.insert(0, "expires soon", Duration::from_secs(5));
+        m.insert(1, "sticks around", Duration::from_secs(100));
+
+        m.clock.advance(Duration::from_secs(10));
+        let drained: Vec<(u64, &str)> = m.drain_expired().collect();
+        assert_eq!(drained, vec![(0, "expires soon")]);
+        assert_eq!(m.get(1), Some(&"sticks around"));
+    }
+
+    #[test]
+    fn heap_cleanup_map_retain() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::default();
+
+        m.insert(0, "a", Duration::from_secs(100));
+        m.insert(1, "b", Duration::from_secs(100));
+
+        m.retain(|&key| key != 1);
+
+        assert_eq!(m.get(0), Some(&"a"));
+        assert_eq!(m.get(1), None);
+    }
+
+    #[test]
+    fn heap_cleanup_map_capacity_evicts_least_recently_inserted() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::with_capacity(2);
+
+        m.insert(0, "a", Duration::from_secs(100));
+        m.insert(1, "b", Duration::from_secs(100));
+        assert_eq!(m.get(0), Some(&"a"));
+        assert_eq!(m.get(1), Some(&"b"));
+
+        m.insert(2, "c", Duration::from_secs(100));
+        assert_eq!(m.get(0), None);
+        assert_eq!(m.get(1), Some(&"b"));
+        assert_eq!(m.get(2), Some(&"c"));
+    }
+
+    #[test]
+    fn heap_cleanup_map_capacity_refresh_moves_key_to_back_of_queue() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::with_capacity(2);
+
+        m.insert(0, "a", Duration::from_secs(100));
+        m.insert(1, "b", Duration::from_secs(100));
+        m.insert(0, "a", Duration::from_secs(100));
+
+        m.insert(2, "c", Duration::from_secs(100));
+        assert_eq!(m.get(0), Some(&"a"));
+        assert_eq!(m.get(1), None);
+        assert_eq!(m.get(2), Some(&"c"));
+    }
+
+    #[test]
+    fn heap_cleanup_map_remove() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::default();
+
+        m.insert(3, "hello", Duration::from_secs(30));
+
+        assert_eq!(m.remove(3), Some("hello"));
+        assert_eq!(m.get(3), None);
+        // The ghost entry left behind in the heap shouldn't cause a second
+        // remove to panic or resurrect the value.
+        assert_eq!(m.remove(3), None);
+    }
 }
@@ -1,9 +1,18 @@
+use std::hash::Hash;
 use std::time::{Duration, Instant};
 
-pub trait TtlSet {
-    fn insert(&mut self, item: u64, duration: Duration);
+pub trait TtlSet<K: Hash + Eq + Clone> {
+    fn insert(&mut self, item: K, duration: Duration);
     // &mut because we want to permit cleanup operations
-    fn contains(&mut self, item: u64) -> bool;
+    fn contains(&mut self, item: K) -> bool;
+}
+
+pub trait TtlMap<K: Hash + Eq + Clone, V> {
+    fn insert(&mut self, key: K, value: V, duration: Duration);
+    // &mut because we want to permit cleanup operations
+    fn get(&mut self, key: K) -> Option<&V>;
+    fn get_mut(&mut self, key: K) -> Option<&mut V>;
+    fn remove(&mut self, key: K) -> Option<V>;
 }
 
 pub trait Clock: Default {
@@ -1,79 +1,362 @@
 use std::cmp::Ordering;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
 use std::time::{Duration, Instant};
 
 use heap::{Handle, MutHeap};
-use {Clock, TtlSet};
+use {Clock, TtlMap, TtlSet};
 
-#[derive(Default)]
-pub struct HeapCleanup<C: Clock> {
+pub struct HeapCleanup<K: Hash + Eq + Clone, C: Clock> {
     clock: C,
-    expiration_index: MutHeap<Expiration>,
-    expiration_times: HashMap<u64, Handle>,
+    expiration_index: MutHeap<Expiration<K>>,
+    expiration_times: HashMap<K, Handle>,
+    capacity: Option<usize>,
+    insertion_order: VecDeque<K>,
 }
 
-struct Expiration {
+// Written by hand instead of `#[derive(Default)]`: the derive would add a
+// `K: Default` bound to this impl, on top of the `MutHeap<Expiration<K>>`
+// field already needing its own hand-written `Default` (see heap.rs) to
+// avoid an unsatisfiable `Expiration<K>: Default`. `with_capacity` below
+// needs a `default()` free of both.
+impl<K: Hash + Eq + Clone, C: Clock> Default for HeapCleanup<K, C> {
+    fn default() -> Self {
+        HeapCleanup {
+            clock: C::default(),
+            expiration_index: MutHeap::default(),
+            expiration_times: HashMap::new(),
+            capacity: None,
+            insertion_order: VecDeque::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, C: Clock> HeapCleanup<K, C> {
+    /// Bounds the number of live entries to `capacity`. Once the heap holds
+    /// that many entries, an `insert` that doesn't free up room via TTL
+    /// expiration evicts the least-recently-inserted entry, removing its
+    /// handle from the heap outright rather than leaving a ghost behind.
+    pub fn with_capacity(capacity: usize) -> HeapCleanup<K, C> {
+        HeapCleanup {
+            capacity: Some(capacity),
+            ..HeapCleanup::default()
+        }
+    }
+}
+
+struct Expiration<K> {
     time: Instant,
-    item: u64,
+    item: K,
 }
-impl Ord for Expiration {
+impl<K> Ord for Expiration<K> {
     // Larger element is the one that expires first, so that a max-heap will pop old elements
     fn cmp(&self, other: &Self) -> Ordering {
         self.time.cmp(&other.time).reverse()
     }
 }
-impl PartialOrd for Expiration {
-    fn partial_cmp(&self, other: &Expiration) -> Option<Ordering> {
+impl<K> PartialOrd for Expiration<K> {
+    fn partial_cmp(&self, other: &Expiration<K>) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
-impl PartialEq for Expiration {
-    fn eq(&self, other: &Expiration) -> bool {
+impl<K> PartialEq for Expiration<K> {
+    fn eq(&self, other: &Expiration<K>) -> bool {
         self.time == other.time
     }
 }
-impl Eq for Expiration {}
+impl<K> Eq for Expiration<K> {}
 
-impl<C: Clock> HeapCleanup<C> {
-    fn incremental_clean(&mut self, threshold: Instant) {
+impl<K: Hash + Eq + Clone, C: Clock> HeapCleanup<K, C> {
+    fn incremental_clean(&mut self, threshold: Instant) -> Vec<K> {
+        let mut expired = Vec::new();
         loop {
             match self.expiration_index.peek_max() {
-                Some(exp) if exp.time <= threshold => self.expiration_times.remove(&exp.item),
+                Some(exp) if exp.time <= threshold => {
+                    let key = exp.item.clone();
+                    self.expiration_times.remove(&key);
+                    expired.push(key);
+                }
                 _ => break,
             };
             self.expiration_index.pop_max();
         }
+        if !expired.is_empty() {
+            self.insertion_order.retain(|k| !expired.contains(k));
+        }
+        expired
     }
-}
 
-impl<C: Clock> TtlSet for HeapCleanup<C> {
-    fn insert(&mut self, item: u64, duration: Duration) {
-        let time = self.clock.now() + duration;
-        match self.expiration_times.entry(item) {
-            Entry::Occupied(occ) => {
-                self.expiration_index.decrement(occ.get(), |x| {
-                    if time < x.time {
-                        x.time = time;
+    fn evict_over_capacity(&mut self, now: Instant) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        if self.expiration_times.len() > capacity {
+            self.incremental_clean(now);
+        }
+        while self.expiration_times.len() > capacity {
+            match self.insertion_order.pop_front() {
+                Some(oldest) => {
+                    if let Some(handle) = self.expiration_times.remove(&oldest) {
+                        self.expiration_index.remove(&handle);
                     }
+                }
+                None => break,
+            };
+        }
+    }
+
+    /// The expiration at the top of the heap, i.e. the one due soonest.
+    ///
+    /// Takes `&mut self` because the underlying `MutHeap::peek_max` does.
+    pub fn next_expiration(&mut self) -> Option<Instant> {
+        self.expiration_index.peek_max().map(|exp| exp.time)
+    }
+
+    /// How long until the soonest expiration, so a caller can arm a single
+    /// wakeup timer instead of polling `contains`.
+    pub fn time_until_next(&mut self) -> Option<Duration> {
+        let next = self.next_expiration()?;
+        let now = self.clock.now();
+        Some(next.saturating_duration_since(now))
+    }
+
+    /// Pops every entry due by `now` off the heap and yields its key,
+    /// dropping its handle rather than discarding it silently.
+    pub fn drain_expired(&mut self) -> impl Iterator<Item = K> {
+        let now = self.clock.now();
+        self.incremental_clean(now).into_iter()
+    }
+
+    /// Expires due entries, then drops any surviving key for which `f`
+    /// returns `false`, removing its handle from the heap too.
+    pub fn retain<F: FnMut(&K) -> bool>(&mut self, mut f: F) {
+        let now = self.clock.now();
+        self.incremental_clean(now);
+        let expiration_index = &mut self.expiration_index;
+        let insertion_order = &mut self.insertion_order;
+        self.expiration_times.retain(|key, handle| {
+            let keep = f(key);
+            if !keep {
+                expiration_index.remove(handle);
+                insertion_order.retain(|k| k != key);
+            }
+            keep
+        });
+    }
+}
+
+impl<K: Hash + Eq + Clone, C: Clock> TtlSet<K> for HeapCleanup<K, C> {
+    fn insert(&mut self, item: K, duration: Duration) {
+        let now = self.clock.now();
+        let time = now + duration;
+        match self.expiration_times.entry(item.clone()) {
+            Entry::Occupied(mut occ) => {
+                // `increment`/`decrement` only percolate in one direction each,
+                // so they can't be used to move a refreshed entry either way in
+                // the heap. Just remove and re-insert it instead.
+                self.expiration_index.remove(occ.get());
+                let handle = self.expiration_index.insert(Expiration {
+                    item: item.clone(),
+                    time,
                 });
-                self.expiration_index.increment(occ.get(), |x| {
-                    if time > x.time {
-                        x.time = time;
+                occ.insert(handle);
+            }
+            Entry::Vacant(vac) => {
+                let handle = self.expiration_index.insert(Expiration {
+                    item: item.clone(),
+                    time,
+                });
+                vac.insert(handle);
+            }
+        }
+        self.insertion_order.retain(|k| k != &item);
+        self.insertion_order.push_back(item);
+        self.evict_over_capacity(now);
+    }
+
+    fn contains(&mut self, item: K) -> bool {
+        let now = self.clock.now();
+        self.incremental_clean(now);
+        self.expiration_times.contains_key(&item)
+    }
+}
+
+/// Like `HeapCleanup`, but stores a value alongside each key instead of just
+/// tracking membership.
+pub struct HeapCleanupMap<K: Hash + Eq + Clone, V, C: Clock> {
+    clock: C,
+    expiration_index: MutHeap<Expiration<K>>,
+    expiration_times: HashMap<K, Handle>,
+    values: HashMap<K, V>,
+    capacity: Option<usize>,
+    insertion_order: VecDeque<K>,
+}
+
+// Written by hand instead of `#[derive(Default)]`, for the same reason as
+// `HeapCleanup` above: the derive would tie this impl to `K: Default`,
+// `V: Default`, and the unsatisfiable `Expiration<K>: Default` chain that
+// `MutHeap`'s own derive would otherwise pull in.
+impl<K: Hash + Eq + Clone, V, C: Clock> Default for HeapCleanupMap<K, V, C> {
+    fn default() -> Self {
+        HeapCleanupMap {
+            clock: C::default(),
+            expiration_index: MutHeap::default(),
+            expiration_times: HashMap::new(),
+            values: HashMap::new(),
+            capacity: None,
+            insertion_order: VecDeque::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, C: Clock> HeapCleanupMap<K, V, C> {
+    /// Bounds the number of entries in the map to `capacity`, the same way
+    /// `HeapCleanup::with_capacity` bounds the set: once the heap holds that
+    /// many keys, an `insert` that doesn't free up room via TTL expiration
+    /// evicts the least-recently-inserted key and its value, with its handle
+    /// removed from the heap outright.
+    pub fn with_capacity(capacity: usize) -> HeapCleanupMap<K, V, C> {
+        HeapCleanupMap {
+            capacity: Some(capacity),
+            ..HeapCleanupMap::default()
+        }
+    }
+
+    fn incremental_clean(&mut self, threshold: Instant) -> Vec<(K, V)> {
+        let mut expired = Vec::new();
+        loop {
+            match self.expiration_index.peek_max() {
+                Some(exp) if exp.time <= threshold => {
+                    let key = exp.item.clone();
+                    self.expiration_times.remove(&key);
+                    if let Some(value) = self.values.remove(&key) {
+                        expired.push((key, value));
                     }
+                }
+                _ => break,
+            };
+            self.expiration_index.pop_max();
+        }
+        if !expired.is_empty() {
+            self.insertion_order
+                .retain(|k| !expired.iter().any(|(id, _)| id == k));
+        }
+        expired
+    }
+
+    fn evict_over_capacity(&mut self, now: Instant) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        if self.expiration_times.len() > capacity {
+            self.incremental_clean(now);
+        }
+        while self.expiration_times.len() > capacity {
+            match self.insertion_order.pop_front() {
+                Some(oldest) => {
+                    self.remove_entry(&oldest);
+                }
+                None => break,
+            };
+        }
+    }
+
+    /// The expiration at the top of the heap, i.e. the map entry due soonest.
+    ///
+    /// Takes `&mut self` because the underlying `MutHeap::peek_max` does.
+    pub fn next_expiration(&mut self) -> Option<Instant> {
+        self.expiration_index.peek_max().map(|exp| exp.time)
+    }
+
+    /// How long until the soonest expiration, so a caller can arm a single
+    /// wakeup timer instead of polling `get`.
+    pub fn time_until_next(&mut self) -> Option<Duration> {
+        let next = self.next_expiration()?;
+        let now = self.clock.now();
+        Some(next.saturating_duration_since(now))
+    }
+
+    /// Pops every entry due by `now` off the heap and yields its
+    /// `(key, value)`, dropping its handle rather than discarding it
+    /// silently.
+    pub fn drain_expired(&mut self) -> impl Iterator<Item = (K, V)> {
+        let now = self.clock.now();
+        self.incremental_clean(now).into_iter()
+    }
+
+    /// Expires due entries, then drops any surviving `(key, value)` pair
+    /// whose key fails `f`, removing its handle from the heap too.
+    pub fn retain<F: FnMut(&K) -> bool>(&mut self, mut f: F) {
+        let now = self.clock.now();
+        self.incremental_clean(now);
+        let values = &mut self.values;
+        let expiration_index = &mut self.expiration_index;
+        self.expiration_times.retain(|key, handle| {
+            let keep = f(key);
+            if !keep {
+                values.remove(key);
+                expiration_index.remove(handle);
+            }
+            keep
+        });
+    }
+
+    fn remove_entry(&mut self, key: &K) -> Option<V> {
+        if let Some(handle) = self.expiration_times.remove(key) {
+            self.expiration_index.remove(&handle);
+        }
+        self.insertion_order.retain(|k| k != key);
+        self.values.remove(key)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, C: Clock> TtlMap<K, V> for HeapCleanupMap<K, V, C> {
+    fn insert(&mut self, key: K, value: V, duration: Duration) {
+        let now = self.clock.now();
+        let time = now + duration;
+        match self.expiration_times.entry(key.clone()) {
+            Entry::Occupied(mut occ) => {
+                // `increment`/`decrement` only percolate in one direction each,
+                // so they can't be used to move a refreshed entry either way in
+                // the heap. Just remove and re-insert it instead.
+                self.expiration_index.remove(occ.get());
+                let handle = self.expiration_index.insert(Expiration {
+                    item: key.clone(),
+                    time,
                 });
+                occ.insert(handle);
             }
             Entry::Vacant(vac) => {
-                let handle = self.expiration_index.insert(Expiration { item, time });
+                let handle = self.expiration_index.insert(Expiration {
+                    item: key.clone(),
+                    time,
+                });
                 vac.insert(handle);
             }
         }
+        self.insertion_order.retain(|k| k != &key);
+        self.insertion_order.push_back(key.clone());
+        self.values.insert(key, value);
+        self.evict_over_capacity(now);
     }
 
-    fn contains(&mut self, item: u64) -> bool {
+    fn get(&mut self, key: K) -> Option<&V> {
         let now = self.clock.now();
         self.incremental_clean(now);
-        self.expiration_times.contains_key(&item)
+        self.values.get(&key)
+    }
+
+    fn get_mut(&mut self, key: K) -> Option<&mut V> {
+        let now = self.clock.now();
+        self.incremental_clean(now);
+        self.values.get_mut(&key)
+    }
+
+    fn remove(&mut self, key: K) -> Option<V> {
+        self.remove_entry(&key)
     }
 }
 
@@ -84,7 +367,7 @@ mod test {
 
     #[test]
     fn tree_cleanup_smoke_test() {
-        let mut m = HeapCleanup::<FakeClock>::default();
+        let mut m = HeapCleanup::<u64, FakeClock>::default();
 
         assert!(!m.contains(0));
 
@@ -100,7 +383,7 @@ mod test {
 
     #[test]
     fn overwriting_entries_wiped_old_expirations() {
-        let mut m = HeapCleanup::<FakeClock>::default();
+        let mut m = HeapCleanup::<u64, FakeClock>::default();
 
         assert!(!m.contains(0));
 
@@ -113,4 +396,206 @@ mod test {
         m.clock.advance(Duration::from_secs(100));
         assert!(m.contains(0));
     }
+
+    #[test]
+    fn heap_cleanup_capacity_evicts_least_recently_inserted() {
+        let mut m = HeapCleanup::<u64, FakeClock>::with_capacity(2);
+
+        m.insert(0, Duration::from_secs(100));
+        m.insert(1, Duration::from_secs(100));
+        assert!(m.contains(0));
+        assert!(m.contains(1));
+
+        m.insert(2, Duration::from_secs(100));
+        assert!(!m.contains(0));
+        assert!(m.contains(1));
+        assert!(m.contains(2));
+    }
+
+    #[test]
+    fn heap_cleanup_capacity_refresh_moves_key_to_back_of_queue() {
+        let mut m = HeapCleanup::<u64, FakeClock>::with_capacity(2);
+
+        m.insert(0, Duration::from_secs(100));
+        m.insert(1, Duration::from_secs(100));
+        m.insert(0, Duration::from_secs(100));
+
+        m.insert(2, Duration::from_secs(100));
+        assert!(m.contains(0));
+        assert!(!m.contains(1));
+        assert!(m.contains(2));
+    }
+
+    #[test]
+    fn heap_cleanup_refresh_internal_node_preserves_heap_invariant() {
+        let mut m = HeapCleanup::<u64, FakeClock>::default();
+
+        m.insert(0, Duration::from_secs(100));
+        m.insert(1, Duration::from_secs(10));
+        m.insert(2, Duration::from_secs(50));
+        m.insert(3, Duration::from_secs(20));
+        m.insert(4, Duration::from_secs(5));
+
+        // Refresh key 1, an internal (non-leaf, non-root) node, to a much
+        // later expiration. A wrong percolate direction here strands key 2
+        // behind the stale node, making it unreachable to incremental_clean.
+        m.insert(1, Duration::from_secs(1000));
+
+        m.clock.advance(Duration::from_secs(60));
+        assert!(!m.contains(2));
+        assert!(!m.contains(3));
+        assert!(!m.contains(4));
+        assert!(m.contains(0));
+        assert!(m.contains(1));
+    }
+
+    #[test]
+    fn heap_cleanup_next_expiration() {
+        let mut m = HeapCleanup::<u64, FakeClock>::default();
+
+        assert_eq!(m.next_expiration(), None);
+
+        m.insert(0, Duration::from_secs(15));
+        m.insert(1, Duration::from_secs(5));
+
+        assert!(m.next_expiration().is_some());
+        assert!(m.time_until_next().unwrap() <= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn heap_cleanup_drain_expired() {
+        let mut m = HeapCleanup::<u64, FakeClock>::default();
+
+        m.insert(0, Duration::from_secs(5));
+        m.insert(1, Duration::from_secs(100));
+
+        m.clock.advance(Duration::from_secs(10));
+        let drained: Vec<u64> = m.drain_expired().collect();
+        assert_eq!(drained, vec![0]);
+        assert!(!m.contains(0));
+        assert!(m.contains(1));
+    }
+
+    #[test]
+    fn heap_cleanup_retain() {
+        let mut m = HeapCleanup::<u64, FakeClock>::default();
+
+        m.insert(0, Duration::from_secs(100));
+        m.insert(1, Duration::from_secs(100));
+        m.insert(2, Duration::from_secs(100));
+
+        m.retain(|&key| key != 1);
+
+        assert!(m.contains(0));
+        assert!(!m.contains(1));
+        assert!(m.contains(2));
+    }
+
+    #[test]
+    fn heap_cleanup_map_smoke_test() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::default();
+
+        assert_eq!(m.get(0), None);
+
+        m.insert(0, "hello", Duration::from_secs(15));
+        assert_eq!(m.get(0), Some(&"hello"));
+
+        m.clock.advance(Duration::from_secs(10));
+        assert_eq!(m.get(0), Some(&"hello"));
+
+        m.clock.advance(Duration::from_secs(10));
+        assert_eq!(m.get(0), None);
+    }
+
+    #[test]
+    fn heap_cleanup_map_drain_expired() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::default();
+
+        m.insert(0, "expires soon", Duration::from_secs(5));
+        m.insert(1, "sticks around", Duration::from_secs(100));
+
+        m.clock.advance(Duration::from_secs(10));
+        let drained: Vec<(u64, &str)> = m.drain_expired().collect();
+        assert_eq!(drained, vec![(0, "expires soon")]);
+        assert_eq!(m.get(1), Some(&"sticks around"));
+    }
+
+    #[test]
+    fn heap_cleanup_map_retain() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::default();
+
+        m.insert(0, "a", Duration::from_secs(100));
+        m.insert(1, "b", Duration::from_secs(100));
+
+        m.retain(|&key| key != 1);
+
+        assert_eq!(m.get(0), Some(&"a"));
+        assert_eq!(m.get(1), None);
+    }
+
+    #[test]
+    fn heap_cleanup_map_refresh_internal_node_preserves_heap_invariant() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::default();
+
+        m.insert(0, "a", Duration::from_secs(100));
+        m.insert(1, "b", Duration::from_secs(10));
+        m.insert(2, "c", Duration::from_secs(50));
+        m.insert(3, "d", Duration::from_secs(20));
+        m.insert(4, "e", Duration::from_secs(5));
+
+        // Refresh key 1, an internal (non-leaf, non-root) node, to a much
+        // later expiration. A wrong percolate direction here strands key 2
+        // behind the stale node, making it unreachable to incremental_clean.
+        m.insert(1, "b", Duration::from_secs(1000));
+
+        m.clock.advance(Duration::from_secs(60));
+        assert_eq!(m.get(2), None);
+        assert_eq!(m.get(3), None);
+        assert_eq!(m.get(4), None);
+        assert_eq!(m.get(0), Some(&"a"));
+        assert_eq!(m.get(1), Some(&"b"));
+    }
+
+    #[test]
+    fn heap_cleanup_map_capacity_evicts_least_recently_inserted() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::with_capacity(2);
+
+        m.insert(0, "a", Duration::from_secs(100));
+        m.insert(1, "b", Duration::from_secs(100));
+        assert_eq!(m.get(0), Some(&"a"));
+        assert_eq!(m.get(1), Some(&"b"));
+
+        m.insert(2, "c", Duration::from_secs(100));
+        assert_eq!(m.get(0), None);
+        assert_eq!(m.get(1), Some(&"b"));
+        assert_eq!(m.get(2), Some(&"c"));
+    }
+
+    #[test]
+    fn heap_cleanup_map_capacity_refresh_moves_key_to_back_of_queue() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::with_capacity(2);
+
+        m.insert(0, "a", Duration::from_secs(100));
+        m.insert(1, "b", Duration::from_secs(100));
+        m.insert(0, "a", Duration::from_secs(100));
+
+        m.insert(2, "c", Duration::from_secs(100));
+        assert_eq!(m.get(0), Some(&"a"));
+        assert_eq!(m.get(1), None);
+        assert_eq!(m.get(2), Some(&"c"));
+    }
+
+    #[test]
+    fn mut_heap_cleanup_map_remove() {
+        let mut m = HeapCleanupMap::<u64, &str, FakeClock>::default();
+
+        m.insert(9, "world", Duration::from_secs(64));
+
+        assert_eq!(m.remove(9), Some("world"));
+        assert_eq!(m.get(9), None);
+        // Unlike the `BinaryHeap`-backed variant, removal here actually
+        // evicts the handle from the heap rather than leaving a ghost.
+        assert_eq!(m.next_expiration(), None);
+        assert_eq!(m.remove(9), None);
+    }
 }
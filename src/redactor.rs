@@ -1,16 +1,17 @@
 use std::collections::HashMap;
+use std::hash::Hash;
 use std::time::{Duration, Instant};
 
 use {Clock, TtlSet};
 /// A simple TtlSet that keeps track of each item's expiration time.
 /// During a `contains` check, it inspects the expiration time; if it is expired, returns `false`.
-pub struct Redactor<C: Clock> {
+pub struct Redactor<K: Hash + Eq + Clone, C: Clock> {
     clock: C,
-    expiration_times: HashMap<u64, Instant>,
+    expiration_times: HashMap<K, Instant>,
 }
 
-impl<C: Clock> Redactor<C> {
-    pub fn new() -> Redactor<C> {
+impl<K: Hash + Eq + Clone, C: Clock> Redactor<K, C> {
+    pub fn new() -> Redactor<K, C> {
         Redactor {
             clock: C::new(),
             expiration_times: HashMap::new(),
@@ -18,13 +19,13 @@ impl<C: Clock> Redactor<C> {
     }
 }
 
-impl<C: Clock> TtlSet for Redactor<C> {
-    fn insert(&mut self, item: u64, duration: Duration) {
+impl<K: Hash + Eq + Clone, C: Clock> TtlSet<K> for Redactor<K, C> {
+    fn insert(&mut self, item: K, duration: Duration) {
         self.expiration_times
             .insert(item, self.clock.now() + duration);
     }
 
-    fn contains(&mut self, key: u64) -> bool {
+    fn contains(&mut self, key: K) -> bool {
         match self.expiration_times.get(&key) {
             Some(expires_at) => self.clock.now() < *expires_at,
             None => false,
@@ -39,7 +40,7 @@ mod test {
 
     #[test]
     fn smoke_test() {
-        let mut m = Redactor::<FakeClock>::new();
+        let mut m = Redactor::<u64, FakeClock>::new();
 
         assert!(!m.contains(0));
 
@@ -1,40 +1,131 @@
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::Hash;
 use std::mem;
 use std::time::{Duration, Instant};
 
-use {Clock, TtlSet};
+use {Clock, TtlMap, TtlSet};
 
-pub struct TreeCleanup<C: Clock> {
+pub struct TreeCleanup<K: Hash + Eq + Clone, C: Clock> {
     clock: C,
-    expiration_times: HashMap<u64, Instant>,
-    expiration_index: BTreeMap<Instant, HashSet<u64>>,
+    expiration_times: HashMap<K, Instant>,
+    expiration_index: BTreeMap<Instant, HashSet<K>>,
+    capacity: Option<usize>,
+    insertion_order: VecDeque<K>,
 }
-impl<C: Clock> TreeCleanup<C> {
-    pub fn new() -> TreeCleanup<C> {
+impl<K: Hash + Eq + Clone, C: Clock> TreeCleanup<K, C> {
+    pub fn new() -> TreeCleanup<K, C> {
         TreeCleanup {
             clock: C::new(),
             expiration_times: HashMap::new(),
             expiration_index: BTreeMap::new(),
+            capacity: None,
+            insertion_order: VecDeque::new(),
+        }
+    }
+
+    /// Bounds the number of live entries to `capacity`. Once the tree holds
+    /// that many entries, an `insert` that doesn't free up room via TTL
+    /// expiration evicts the least-recently-inserted entry.
+    pub fn with_capacity(capacity: usize) -> TreeCleanup<K, C> {
+        TreeCleanup {
+            capacity: Some(capacity),
+            ..TreeCleanup::new()
         }
     }
 }
 
-impl<C: Clock> TreeCleanup<C> {
-    fn incremental_clean(&mut self, threshold: Instant) {
+impl<K: Hash + Eq + Clone, C: Clock> TreeCleanup<K, C> {
+    fn incremental_clean(&mut self, threshold: Instant) -> Vec<K> {
         let mut tmp = self.expiration_index.split_off(&threshold);
         mem::swap(&mut self.expiration_index, &mut tmp);
+        let mut expired = Vec::new();
         for (_expiry, ids) in tmp {
             for id in ids {
                 self.expiration_times.remove(&id);
+                expired.push(id);
             }
         }
+        if !expired.is_empty() {
+            self.insertion_order.retain(|k| !expired.contains(k));
+        }
+        expired
+    }
+
+    fn remove_oldest(&mut self, key: &K) {
+        if let Some(expiry) = self.expiration_times.remove(key) {
+            let size_after_deleting = {
+                let ids = self
+                    .expiration_index
+                    .get_mut(&expiry)
+                    .expect("the evicted entry must have had an expiration time registered");
+                ids.remove(key);
+                ids.len()
+            };
+            if size_after_deleting == 0 {
+                self.expiration_index.remove(&expiry);
+            }
+        }
+        self.insertion_order.retain(|k| k != key);
+    }
+
+    fn evict_over_capacity(&mut self, now: Instant) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        if self.expiration_times.len() > capacity {
+            self.incremental_clean(now);
+        }
+        while self.expiration_times.len() > capacity {
+            match self.insertion_order.pop_front() {
+                Some(oldest) => self.remove_oldest(&oldest),
+                None => break,
+            }
+        }
+    }
+
+    /// The earliest expiration in the tree, if any entries are tracked.
+    pub fn next_expiration(&self) -> Option<Instant> {
+        self.expiration_index.keys().next().cloned()
+    }
+
+    /// How long until that earliest expiration, so a caller can arm a single
+    /// wakeup timer instead of polling `contains`.
+    pub fn time_until_next(&mut self) -> Option<Duration> {
+        let next = self.next_expiration()?;
+        let now = self.clock.now();
+        Some(next.saturating_duration_since(now))
+    }
+
+    /// Splits the tree at `now` and yields the keys on the expired side,
+    /// instead of discarding them silently.
+    pub fn drain_expired(&mut self) -> impl Iterator<Item = K> {
+        let now = self.clock.now();
+        self.incremental_clean(now).into_iter()
+    }
+
+    /// Expires due entries, then drops any surviving key for which `f`
+    /// returns `false`.
+    pub fn retain<F: FnMut(&K) -> bool>(&mut self, mut f: F) {
+        let now = self.clock.now();
+        self.incremental_clean(now);
+        let to_remove: Vec<K> = self
+            .expiration_times
+            .keys()
+            .filter(|key| !f(key))
+            .cloned()
+            .collect();
+        for key in to_remove {
+            self.remove_oldest(&key);
+        }
     }
 }
 
-impl<C: Clock> TtlSet for TreeCleanup<C> {
-    fn insert(&mut self, item: u64, duration: Duration) {
-        let expiry = self.clock.now() + duration;
-        if let Some(prev) = self.expiration_times.insert(item, expiry) {
+impl<K: Hash + Eq + Clone, C: Clock> TtlSet<K> for TreeCleanup<K, C> {
+    fn insert(&mut self, item: K, duration: Duration) {
+        let now = self.clock.now();
+        let expiry = now + duration;
+        if let Some(prev) = self.expiration_times.insert(item.clone(), expiry) {
             let size_after_deleting = {
                 let ids_to_expire = self
                     .expiration_index
@@ -50,16 +141,191 @@ impl<C: Clock> TtlSet for TreeCleanup<C> {
         self.expiration_index
             .entry(expiry)
             .or_insert_with(HashSet::new)
-            .insert(item);
+            .insert(item.clone());
+        self.insertion_order.retain(|k| k != &item);
+        self.insertion_order.push_back(item);
+        self.evict_over_capacity(now);
     }
 
-    fn contains(&mut self, item: u64) -> bool {
+    fn contains(&mut self, item: K) -> bool {
         let now = self.clock.now();
         self.incremental_clean(now);
         self.expiration_times.contains_key(&item)
     }
 }
 
+/// Like `TreeCleanup`, but stores a value alongside each key instead of just
+/// tracking membership.
+pub struct TreeCleanupMap<K: Hash + Eq + Clone, V, C: Clock> {
+    clock: C,
+    expiration_times: HashMap<K, Instant>,
+    expiration_index: BTreeMap<Instant, HashSet<K>>,
+    values: HashMap<K, V>,
+    capacity: Option<usize>,
+    insertion_order: VecDeque<K>,
+}
+impl<K: Hash + Eq + Clone, V, C: Clock> TreeCleanupMap<K, V, C> {
+    pub fn new() -> TreeCleanupMap<K, V, C> {
+        TreeCleanupMap {
+            clock: C::new(),
+            expiration_times: HashMap::new(),
+            expiration_index: BTreeMap::new(),
+            values: HashMap::new(),
+            capacity: None,
+            insertion_order: VecDeque::new(),
+        }
+    }
+
+    /// Bounds the number of entries in the map to `capacity`, the same way
+    /// `TreeCleanup::with_capacity` bounds the set: once the tree holds that
+    /// many keys, an `insert` that doesn't free up room via TTL expiration
+    /// evicts the least-recently-inserted key and its value.
+    pub fn with_capacity(capacity: usize) -> TreeCleanupMap<K, V, C> {
+        TreeCleanupMap {
+            capacity: Some(capacity),
+            ..TreeCleanupMap::new()
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, C: Clock> TreeCleanupMap<K, V, C> {
+    fn incremental_clean(&mut self, threshold: Instant) -> Vec<(K, V)> {
+        let mut tmp = self.expiration_index.split_off(&threshold);
+        mem::swap(&mut self.expiration_index, &mut tmp);
+        let mut expired = Vec::new();
+        for (_expiry, ids) in tmp {
+            for id in ids {
+                self.expiration_times.remove(&id);
+                if let Some(value) = self.values.remove(&id) {
+                    expired.push((id, value));
+                }
+            }
+        }
+        if !expired.is_empty() {
+            self.insertion_order
+                .retain(|k| !expired.iter().any(|(id, _)| id == k));
+        }
+        expired
+    }
+
+    fn evict_over_capacity(&mut self, now: Instant) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        if self.expiration_times.len() > capacity {
+            self.incremental_clean(now);
+        }
+        while self.expiration_times.len() > capacity {
+            match self.insertion_order.pop_front() {
+                Some(oldest) => {
+                    self.remove_entry(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// The earliest expiration among the map's entries, if any.
+    pub fn next_expiration(&self) -> Option<Instant> {
+        self.expiration_index.keys().next().cloned()
+    }
+
+    /// How long until that earliest expiration, so a caller can arm a single
+    /// wakeup timer instead of polling `get`.
+    pub fn time_until_next(&mut self) -> Option<Duration> {
+        let next = self.next_expiration()?;
+        let now = self.clock.now();
+        Some(next.saturating_duration_since(now))
+    }
+
+    /// Splits the tree at `now` and yields the `(key, value)` pairs on the
+    /// expired side, instead of discarding them silently.
+    pub fn drain_expired(&mut self) -> impl Iterator<Item = (K, V)> {
+        let now = self.clock.now();
+        self.incremental_clean(now).into_iter()
+    }
+
+    /// Expires due entries, then drops any surviving `(key, value)` pair
+    /// whose key fails `f`.
+    pub fn retain<F: FnMut(&K) -> bool>(&mut self, mut f: F) {
+        let now = self.clock.now();
+        self.incremental_clean(now);
+        let to_remove: Vec<K> = self
+            .expiration_times
+            .keys()
+            .filter(|key| !f(key))
+            .cloned()
+            .collect();
+        for key in to_remove {
+            self.remove_entry(&key);
+        }
+    }
+
+    fn remove_entry(&mut self, key: &K) -> Option<V> {
+        let value = self.values.remove(key);
+        if let Some(expiry) = self.expiration_times.remove(key) {
+            let size_after_deleting = {
+                let ids = self
+                    .expiration_index
+                    .get_mut(&expiry)
+                    .expect("the removed entry must have had an expiration time registered");
+                ids.remove(key);
+                ids.len()
+            };
+            if size_after_deleting == 0 {
+                self.expiration_index.remove(&expiry);
+            }
+        }
+        self.insertion_order.retain(|k| k != key);
+        value
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, C: Clock> TtlMap<K, V> for TreeCleanupMap<K, V, C> {
+    fn insert(&mut self, key: K, value: V, duration: Duration) {
+        let now = self.clock.now();
+        let expiry = now + duration;
+        if let Some(prev) = self.expiration_times.insert(key.clone(), expiry) {
+            let size_after_deleting = {
+                let ids_to_expire = self
+                    .expiration_index
+                    .get_mut(&prev)
+                    .expect("the previous entry must have had an expiration time registered");
+                ids_to_expire.remove(&key);
+                ids_to_expire.len()
+            };
+            if size_after_deleting == 0 {
+                self.expiration_index.remove(&prev);
+            }
+        }
+        self.expiration_index
+            .entry(expiry)
+            .or_insert_with(HashSet::new)
+            .insert(key.clone());
+        self.insertion_order.retain(|k| k != &key);
+        self.insertion_order.push_back(key.clone());
+        self.values.insert(key, value);
+        self.evict_over_capacity(now);
+    }
+
+    fn get(&mut self, key: K) -> Option<&V> {
+        let now = self.clock.now();
+        self.incremental_clean(now);
+        self.values.get(&key)
+    }
+
+    fn get_mut(&mut self, key: K) -> Option<&mut V> {
+        let now = self.clock.now();
+        self.incremental_clean(now);
+        self.values.get_mut(&key)
+    }
+
+    fn remove(&mut self, key: K) -> Option<V> {
+        self.remove_entry(&key)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -67,7 +333,7 @@ mod test {
 
     #[test]
     fn tree_cleanup_smoke_test() {
-        let mut m = TreeCleanup::<FakeClock>::new();
+        let mut m = TreeCleanup::<u64, FakeClock>::new();
 
         assert!(!m.contains(0));
 
@@ -83,7 +349,7 @@ mod test {
 
     #[test]
     fn overwriting_entries_wiped_old_expirations() {
-        let mut m = TreeCleanup::<FakeClock>::new();
+        let mut m = TreeCleanup::<u64, FakeClock>::new();
 
         assert!(!m.contains(0));
 
@@ -96,4 +362,158 @@ mod test {
         m.clock.advance(Duration::from_secs(100));
         assert!(m.contains(0));
     }
+
+    #[test]
+    fn tree_cleanup_capacity_evicts_least_recently_inserted() {
+        let mut m = TreeCleanup::<u64, FakeClock>::with_capacity(2);
+
+        m.insert(0, Duration::from_secs(100));
+        m.insert(1, Duration::from_secs(100));
+        assert!(m.contains(0));
+        assert!(m.contains(1));
+
+        m.insert(2, Duration::from_secs(100));
+        assert!(!m.contains(0));
+        assert!(m.contains(1));
+        assert!(m.contains(2));
+    }
+
+    #[test]
+    fn tree_cleanup_capacity_refresh_moves_key_to_back_of_queue() {
+        let mut m = TreeCleanup::<u64, FakeClock>::with_capacity(2);
+
+        m.insert(0, Duration::from_secs(100));
+        m.insert(1, Duration::from_secs(100));
+        m.insert(0, Duration::from_secs(100));
+
+        m.insert(2, Duration::from_secs(100));
+        assert!(m.contains(0));
+        assert!(!m.contains(1));
+        assert!(m.contains(2));
+    }
+
+    #[test]
+    fn tree_cleanup_next_expiration() {
+        let mut m = TreeCleanup::<u64, FakeClock>::new();
+
+        assert_eq!(m.next_expiration(), None);
+
+        m.insert(0, Duration::from_secs(15));
+        m.insert(1, Duration::from_secs(5));
+        assert_eq!(m.next_expiration(), Some(m.expiration_times[&1]));
+
+        assert!(m.time_until_next().unwrap() <= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn tree_cleanup_drain_expired() {
+        let mut m = TreeCleanup::<u64, FakeClock>::new();
+
+        m.insert(0, Duration::from_secs(5));
+        m.insert(1, Duration::from_secs(100));
+
+        m.clock.advance(Duration::from_secs(10));
+        let mut drained: Vec<u64> = m.drain_expired().collect();
+        drained.sort();
+        assert_eq!(drained, vec![0]);
+        assert!(!m.contains(0));
+        assert!(m.contains(1));
+    }
+
+    #[test]
+    fn tree_cleanup_retain() {
+        let mut m = TreeCleanup::<u64, FakeClock>::new();
+
+        m.insert(0, Duration::from_secs(100));
+        m.insert(1, Duration::from_secs(100));
+        m.insert(2, Duration::from_secs(100));
+
+        m.retain(|&key| key != 1);
+
+        assert!(m.contains(0));
+        assert!(!m.contains(1));
+        assert!(m.contains(2));
+    }
+
+    #[test]
+    fn tree_cleanup_map_smoke_test() {
+        let mut m = TreeCleanupMap::<u64, &str, FakeClock>::new();
+
+        assert_eq!(m.get(0), None);
+
+        m.insert(0, "hello", Duration::from_secs(15));
+        assert_eq!(m.get(0), Some(&"hello"));
+
+        m.clock.advance(Duration::from_secs(10));
+        assert_eq!(m.get(0), Some(&"hello"));
+
+        m.clock.advance(Duration::from_secs(10));
+        assert_eq!(m.get(0), None);
+    }
+
+    #[test]
+    fn tree_cleanup_map_drain_expired() {
+        let mut m = TreeCleanupMap::<u64, &str, FakeClock>::new();
+
+        m.insert(0, "expires soon", Duration::from_secs(5));
+        m.insert(1, "sticks around", Duration::from_secs(100));
+
+        m.clock.advance(Duration::from_secs(10));
+        let drained: Vec<(u64, &str)> = m.drain_expired().collect();
+        assert_eq!(drained, vec![(0, "expires soon")]);
+        assert_eq!(m.get(1), Some(&"sticks around"));
+    }
+
+    #[test]
+    fn tree_cleanup_map_retain() {
+        let mut m = TreeCleanupMap::<u64, &str, FakeClock>::new();
+
+        m.insert(0, "a", Duration::from_secs(100));
+        m.insert(1, "b", Duration::from_secs(100));
+
+        m.retain(|&key| key != 1);
+
+        assert_eq!(m.get(0), Some(&"a"));
+        assert_eq!(m.get(1), None);
+    }
+
+    #[test]
+    fn tree_cleanup_map_capacity_evicts_least_recently_inserted() {
+        let mut m = TreeCleanupMap::<u64, &str, FakeClock>::with_capacity(2);
+
+        m.insert(0, "a", Duration::from_secs(100));
+        m.insert(1, "b", Duration::from_secs(100));
+        assert_eq!(m.get(0), Some(&"a"));
+        assert_eq!(m.get(1), Some(&"b"));
+
+        m.insert(2, "c", Duration::from_secs(100));
+        assert_eq!(m.get(0), None);
+        assert_eq!(m.get(1), Some(&"b"));
+        assert_eq!(m.get(2), Some(&"c"));
+    }
+
+    #[test]
+    fn tree_cleanup_map_capacity_refresh_moves_key_to_back_of_queue() {
+        let mut m = TreeCleanupMap::<u64, &str, FakeClock>::with_capacity(2);
+
+        m.insert(0, "a", Duration::from_secs(100));
+        m.insert(1, "b", Duration::from_secs(100));
+        m.insert(0, "a", Duration::from_secs(100));
+
+        m.insert(2, "c", Duration::from_secs(100));
+        assert_eq!(m.get(0), Some(&"a"));
+        assert_eq!(m.get(1), None);
+        assert_eq!(m.get(2), Some(&"c"));
+    }
+
+    #[test]
+    fn tree_cleanup_map_remove() {
+        let mut m = TreeCleanupMap::<u64, &str, FakeClock>::new();
+
+        m.insert(7, "first", Duration::from_secs(42));
+
+        assert_eq!(m.remove(7), Some("first"));
+        assert_eq!(m.get(7), None);
+        assert_eq!(m.remove(7), None);
+    }
 }